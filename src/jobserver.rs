@@ -0,0 +1,144 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{mkfifo, pipe, write};
+
+/// A GNU Make-compatible jobserver, with `tap` acting as the token server.
+///
+/// Implements the classic POSIX jobserver protocol: the pipe (or FIFO) is
+/// pre-filled with `n_jobs - 1` single-byte tokens, since the process that
+/// creates the jobserver implicitly holds one token itself. A child wanting
+/// to run an extra parallel job blocks reading one byte to acquire a token,
+/// and writes a byte back once that job finishes. The read/write ends are
+/// handed to children through `MAKEFLAGS`, which both `make` and recent
+/// `ninja` understand.
+pub enum JobServer {
+    Fifo { path: PathBuf, file: File },
+    Pipe { read_fd: RawFd, write_fd: RawFd },
+}
+
+impl JobServer {
+    /// Set up a jobserver with `n_jobs - 1` tokens available for children to
+    /// acquire. Returns `Ok(None)` when there is nothing to share (`n_jobs`
+    /// is `0` or `1`) or when the platform can't give us what we need, in
+    /// which case callers should fall back to passing a plain `-j <n_jobs>`.
+    pub fn new(n_jobs: usize) -> Result<Option<Self>> {
+        if n_jobs <= 1 {
+            return Ok(None);
+        }
+        let tokens = n_jobs - 1;
+
+        // The `R,W` fd form is understood by GNU make since 3.81; the
+        // `fifo:` form only since 4.4. Prefer the fd form so we don't hand
+        // an older make an auth string it silently ignores, which would
+        // have it fall back to unlimited parallelism instead of to our
+        // `-j <n_jobs>`.
+        if let Ok(jobserver) = Self::create_pipe(tokens) {
+            return Ok(Some(jobserver));
+        }
+        if let Ok(jobserver) = Self::create_fifo(tokens) {
+            return Ok(Some(jobserver));
+        }
+        Ok(None)
+    }
+
+    fn create_fifo(tokens: usize) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("tap-jobserver-{}.fifo", std::process::id()));
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)
+            .with_context(|| "Couldn't create jobserver FIFO")?;
+
+        // Open for read-write so the FIFO always has a reader on our end;
+        // opening it write-only would block (or fail) until some other
+        // process opened it for reading first.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(&path)
+            .with_context(|| "Couldn't open jobserver FIFO")?;
+        set_inheritable(file.as_raw_fd())?;
+
+        for _ in 0..tokens {
+            write_fifo_token(&mut file)?;
+        }
+
+        Ok(Self::Fifo { path, file })
+    }
+
+    fn create_pipe(tokens: usize) -> Result<Self> {
+        let (read_fd, write_fd) = pipe().with_context(|| "Couldn't create jobserver pipe")?;
+        set_inheritable(read_fd)?;
+        set_inheritable(write_fd)?;
+
+        for _ in 0..tokens {
+            write_pipe_token(write_fd)?;
+        }
+
+        Ok(Self::Pipe { read_fd, write_fd })
+    }
+
+    /// The `MAKEFLAGS` fragment that advertises this jobserver to children,
+    /// e.g. `-j --jobserver-auth=3,4` or `-j --jobserver-auth=fifo:/tmp/...`.
+    pub fn makeflags(&self) -> String {
+        match self {
+            Self::Fifo { path, .. } => {
+                format!("-j --jobserver-auth=fifo:{}", path.to_string_lossy())
+            }
+            Self::Pipe { read_fd, write_fd } => {
+                format!("-j --jobserver-auth={},{}", read_fd, write_fd)
+            }
+        }
+    }
+}
+
+fn set_inheritable(fd: RawFd) -> Result<()> {
+    let flags = fcntl(fd, FcntlArg::F_GETFD).with_context(|| "Couldn't get jobserver fd flags")?;
+    let flags = FdFlag::from_bits_truncate(flags) & !FdFlag::FD_CLOEXEC;
+    fcntl(fd, FcntlArg::F_SETFD(flags))
+        .with_context(|| "Couldn't mark jobserver fd as inheritable")?;
+    Ok(())
+}
+
+fn write_pipe_token(write_fd: RawFd) -> Result<()> {
+    loop {
+        match write(write_fd, &[b'+']) {
+            Ok(_) => return Ok(()),
+            Err(nix::Error::EINTR) => continue,
+            Err(e) => return Err(e).with_context(|| "Couldn't write jobserver token"),
+        }
+    }
+}
+
+fn write_fifo_token(file: &mut File) -> Result<()> {
+    loop {
+        match file.write_all(&[b'+']) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).with_context(|| "Couldn't write jobserver token"),
+        }
+    }
+}
+
+impl Drop for JobServer {
+    fn drop(&mut self) {
+        // Closes tap's own ends of the pipe, or removes the FIFO from the
+        // filesystem (its open file is closed when `file` is dropped).
+        // Tokens still held by a child are not returned; they are discarded
+        // along with the pool.
+        match self {
+            Self::Fifo { path, .. } => {
+                let _ = std::fs::remove_file(path);
+            }
+            Self::Pipe { read_fd, write_fd } => {
+                let _ = nix::unistd::close(*read_fd);
+                let _ = nix::unistd::close(*write_fd);
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    io::Write,
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
@@ -8,6 +9,10 @@ use anyhow::{Context, Error, Result};
 use clap::{AppSettings, ArgEnum, Parser};
 use colored::Colorize;
 
+use jobserver::JobServer;
+
+mod jobserver;
+
 #[derive(Parser)]
 #[clap(version, author, about)]
 struct Opts {
@@ -20,10 +25,23 @@ struct Opts {
     build_mode: BuildMode,
 
     /// How many jobs to use for compilation.
-    /// Defaults to the number of available threads
+    /// Defaults to `NUM_JOBS`, then `RAYON_NUM_THREADS`, then the number of available threads
     #[clap(short, long, global = true)]
     n_jobs: Option<usize>,
 
+    /// Print the commands that would be run, without actually running them.
+    /// No short flag, since `-n` is already taken by `--n-jobs`
+    #[clap(long, global = true)]
+    dry_run: bool,
+
+    /// Print more information about the commands being run. Can be repeated
+    #[clap(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+
+    /// Capture the build tool's output, and only show it if the command fails
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
     #[clap(subcommand)]
     subcommand: Subcommand,
 }
@@ -68,6 +86,30 @@ pub enum BuildMode {
     Release,
 }
 
+/// How much to say about the commands being run, derived from `--verbose`
+/// and `--quiet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verbosity {
+    /// Capture the child's output and only show it if the command fails.
+    Quiet,
+    /// Today's default: just the ` run `/` cd ` banners.
+    Normal,
+    /// Also print the resolved working directory and full argv.
+    Verbose,
+}
+
+impl Verbosity {
+    fn from_opts(verbose: u8, quiet: bool) -> Self {
+        if quiet {
+            Self::Quiet
+        } else if verbose > 0 {
+            Self::Verbose
+        } else {
+            Self::Normal
+        }
+    }
+}
+
 enum Tap<'a> {
     ChangeDirectory {
         path: &'a Path,
@@ -76,6 +118,9 @@ enum Tap<'a> {
         command: &'a str,
         args: &'a [&'a str],
     },
+    Wait {
+        message: &'a str,
+    },
 }
 
 impl<'a> Tap<'a> {
@@ -83,6 +128,7 @@ impl<'a> Tap<'a> {
         match self {
             Self::ChangeDirectory { .. } => ("cd", "yellow"),
             Self::RunCommand { .. } => ("run", "purple"),
+            Self::Wait { .. } => ("wait", "cyan"),
         }
     }
 
@@ -90,6 +136,7 @@ impl<'a> Tap<'a> {
         match self {
             Self::ChangeDirectory { path } => path.to_string_lossy().into(),
             Self::RunCommand { command, args } => command_to_string(command, args),
+            Self::Wait { message } => message.to_string(),
         }
     }
 
@@ -137,16 +184,67 @@ fn command_to_string(command: &str, args: &[&str]) -> String {
     shell_words::join(std::iter::once(&command).chain(args.iter()))
 }
 
-fn run_command(command: &str, args: &[&str]) -> Result<()> {
+/// Deduce the default job count when `--n-jobs` wasn't given, following the
+/// same precedence as the `cc` crate: `NUM_JOBS`, then `RAYON_NUM_THREADS`,
+/// then the number of available threads. This lets `tap` cooperate when it
+/// is itself invoked from inside another build system's recipe that has
+/// already budgeted a job count, instead of oversubscribing the CPU.
+fn default_n_jobs() -> usize {
+    for var in ["NUM_JOBS", "RAYON_NUM_THREADS"] {
+        if let Some(n) = std::env::var(var)
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .filter(|&n| n > 0)
+        {
+            return n;
+        }
+    }
+    num_cpus::get()
+}
+
+fn run_command(command: &str, args: &[&str], dry_run: bool, verbosity: Verbosity) -> Result<()> {
     {
         Tap::RunCommand { command, args }.print();
 
-        let exit_status = Command::new(command)
-            .args(args)
-            .spawn()
-            .with_context(|| "Couldn't spawn process")?
-            .wait()
-            .with_context(|| "Couldn't wait for process to finish")?;
+        if verbosity == Verbosity::Verbose {
+            println!(
+                "      {} {}",
+                "cwd:".dimmed(),
+                std::env::current_dir()
+                    .with_context(|| "Couldn't get current directory")?
+                    .to_string_lossy()
+            );
+            println!(
+                "      {} {}",
+                "argv:".dimmed(),
+                command_to_string(command, args)
+            );
+        }
+
+        if dry_run {
+            return Ok(());
+        }
+
+        let exit_status = if verbosity == Verbosity::Quiet {
+            let output = Command::new(command)
+                .args(args)
+                .output()
+                .with_context(|| "Couldn't spawn process")?;
+
+            if !output.status.success() {
+                std::io::stdout().write_all(&output.stdout).ok();
+                std::io::stderr().write_all(&output.stderr).ok();
+            }
+
+            output.status
+        } else {
+            Command::new(command)
+                .args(args)
+                .spawn()
+                .with_context(|| "Couldn't spawn process")?
+                .wait()
+                .with_context(|| "Couldn't wait for process to finish")?
+        };
 
         if exit_status.success() {
             Ok(())
@@ -160,11 +258,110 @@ fn run_command(command: &str, args: &[&str]) -> Result<()> {
     .with_context(|| format!("While running command {}", command_to_string(command, args)))
 }
 
+/// Set up a jobserver for `n_jobs` and advertise it to children through
+/// `MAKEFLAGS`, so a Make or Meson build that spawns sub-makes shares our
+/// job budget instead of oversubscribing the CPU. The returned `JobServer`
+/// must be kept alive for as long as the child may still be reading tokens
+/// from it.
+fn with_jobserver_env(n_jobs: usize) -> Result<Option<JobServer>> {
+    let jobserver = JobServer::new(n_jobs)?;
+
+    if let Some(jobserver) = &jobserver {
+        let makeflags = match std::env::var("MAKEFLAGS") {
+            Ok(existing) if !existing.is_empty() => {
+                format!("{} {}", existing, jobserver.makeflags())
+            }
+            _ => jobserver.makeflags(),
+        };
+        std::env::set_var("MAKEFLAGS", makeflags);
+    }
+
+    Ok(jobserver)
+}
+
+/// The `-j` argument(s) to pass to the build tool. GNU make (and recent
+/// ninja) treat an explicit `-jN` as a request to open a *new* top-level
+/// jobserver, ignoring any inherited `--jobserver-auth` in `MAKEFLAGS` — so
+/// once we've installed a jobserver, we must pass a bare `-j` instead of
+/// `-j <n_jobs>`, or the shared token pool is never actually used.
+fn jobs_args(n_jobs: usize, jobserver: &Option<JobServer>) -> Vec<String> {
+    if jobserver.is_some() {
+        vec!["-j".to_string()]
+    } else {
+        vec!["-j".to_string(), n_jobs.to_string()]
+    }
+}
+
+/// Acquire an exclusive lock over the build directory for `build_mode`,
+/// then run `subcommand`. This guards against two concurrent `tap`
+/// invocations (or a `build` racing a `clean`) clobbering the same
+/// `.tap_build_*` directory. The lock is only taken here, at the outermost
+/// entry point; `perform_subcommand_inner` recurses into itself directly so
+/// that `run`/`install` building first doesn't try to re-acquire it.
+///
+/// In dry-run mode, nothing is touched on disk: we skip straight to
+/// `perform_subcommand_inner`, which itself won't run anything either.
 fn perform_subcommand(
     subcommand: &Subcommand,
     build_system: BuildSystem,
     build_mode: BuildMode,
     n_jobs: usize,
+    dry_run: bool,
+    verbosity: Verbosity,
+) -> Result<()> {
+    if dry_run {
+        return perform_subcommand_inner(
+            subcommand,
+            build_system,
+            build_mode,
+            n_jobs,
+            dry_run,
+            verbosity,
+        );
+    }
+
+    let lock_path = std::env::current_dir()
+        .with_context(|| "Couldn't get current directory")?
+        .join(match build_mode {
+            BuildMode::Debug => ".tap_build_debug.lock",
+            BuildMode::Release => ".tap_build_release.lock",
+        });
+    let lock_file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Couldn't open lock file {}", lock_path.to_string_lossy()))?;
+    let mut lock = fd_lock::RwLock::new(lock_file);
+
+    let _guard = match lock.try_write() {
+        Ok(guard) => guard,
+        Err(_) => {
+            Tap::Wait {
+                message: "waiting for another tap process to finish...",
+            }
+            .print();
+            lock.write()
+                .with_context(|| "Couldn't acquire the build directory lock")?
+        }
+    };
+
+    perform_subcommand_inner(
+        subcommand,
+        build_system,
+        build_mode,
+        n_jobs,
+        dry_run,
+        verbosity,
+    )
+}
+
+fn perform_subcommand_inner(
+    subcommand: &Subcommand,
+    build_system: BuildSystem,
+    build_mode: BuildMode,
+    n_jobs: usize,
+    dry_run: bool,
+    verbosity: Verbosity,
 ) -> Result<()> {
     let build_dir = std::env::current_dir()
         .with_context(|| "Couldn't get current directory")?
@@ -176,13 +373,70 @@ fn perform_subcommand(
 
     match subcommand {
         Subcommand::Build => match build_system {
-            BuildSystem::Make => match build_mode {
-                BuildMode::Debug => run_command("make", &["-j", &n_jobs.to_string()]),
-                BuildMode::Release => {
-                    run_command("make", &["CFLAGS=-O3", "-j", &n_jobs.to_string()])
+            BuildSystem::Make => {
+                let jobserver = if dry_run {
+                    None
+                } else {
+                    with_jobserver_env(n_jobs)?
+                };
+                let jobs = if dry_run && n_jobs > 1 {
+                    // A real run sets up a jobserver here and then passes a
+                    // bare `-j`; print that same command.
+                    vec!["-j".to_string()]
+                } else {
+                    jobs_args(n_jobs, &jobserver)
+                };
+                let jobs = jobs.iter().map(String::as_str);
+                match build_mode {
+                    BuildMode::Debug => {
+                        let args = jobs.collect::<Vec<_>>();
+                        run_command("make", &args, dry_run, verbosity)
+                    }
+                    BuildMode::Release => {
+                        let args =
+                            std::iter::once("CFLAGS=-O3").chain(jobs).collect::<Vec<_>>();
+                        run_command("make", &args, dry_run, verbosity)
+                    }
+                }
+            }
+            BuildSystem::CMake => {
+                if !build_dir.exists() {
+                    match run_command(
+                        "cmake",
+                        &[
+                            "-S",
+                            ".",
+                            "-B",
+                            build_dir_str,
+                            &format!(
+                                "-DCMAKE_BUILD_TYPE={}",
+                                match build_mode {
+                                    BuildMode::Debug => "Debug",
+                                    BuildMode::Release => "Release",
+                                },
+                            ),
+                        ],
+                        dry_run,
+                        verbosity,
+                    ) {
+                        Ok(()) => Ok(()),
+                        Err(e) => {
+                            if build_dir.exists() {
+                                std::fs::remove_dir_all(&build_dir)
+                                    .with_context(|| "Couldn't clean up partial build directory")?;
+                            }
+                            Err(e)
+                        }
+                    }?;
                 }
-            },
-            BuildSystem::CMake => todo!(),
+
+                run_command(
+                    "cmake",
+                    &["--build", build_dir_str, "-j", &n_jobs.to_string()],
+                    dry_run,
+                    verbosity,
+                )
+            }
             BuildSystem::Meson => {
                 if !build_dir.exists() {
                     match run_command(
@@ -198,6 +452,8 @@ fn perform_subcommand(
                             ),
                             build_dir_str,
                         ],
+                        dry_run,
+                        verbosity,
                     ) {
                         Ok(()) => Ok(()),
                         Err(e) => {
@@ -210,36 +466,83 @@ fn perform_subcommand(
                     }?;
                 }
 
+                // Unlike `make`, meson's `-j`/`--jobs` always requires an
+                // explicit integer argument, so we can't drop down to a bare
+                // `-j` here the way we do for Make: keep passing `-j
+                // <n_jobs>` regardless of whether a jobserver was set up.
+                // Given that explicit `-j`, ninja runs its own jobs without
+                // taking tokens from the jobserver (see `jobs_args`); the
+                // sub-makes it spawns still inherit `MAKEFLAGS` and share
+                // the token pool.
+                let _jobserver = if dry_run {
+                    None
+                } else {
+                    with_jobserver_env(n_jobs)?
+                };
                 run_command(
                     "meson",
                     &["compile", "-C", build_dir_str, "-j", &n_jobs.to_string()],
+                    dry_run,
+                    verbosity,
                 )
             }
         },
         Subcommand::Run { executable, args } => {
-            perform_subcommand(&Subcommand::Build, build_system, build_mode, n_jobs)
-                .with_context(|| "While building the binary")?;
+            perform_subcommand_inner(
+                &Subcommand::Build,
+                build_system,
+                build_mode,
+                n_jobs,
+                dry_run,
+                verbosity,
+            )
+            .with_context(|| "While building the binary")?;
 
             let args = args.iter().map(String::as_str).collect::<Vec<_>>();
             let args = args.as_slice();
+
+            // `-q` is about quieting build noise, not the program being
+            // run: always let the target's own output straight through.
+            let target_verbosity = match verbosity {
+                Verbosity::Quiet => Verbosity::Normal,
+                verbosity => verbosity,
+            };
+
             match build_system {
-                BuildSystem::Make => run_command(executable, args),
-                BuildSystem::CMake => todo!(),
-                BuildSystem::Meson => {
-                    run_command(&build_dir.join(executable).to_string_lossy(), args)
-                }
+                BuildSystem::Make => run_command(executable, args, dry_run, target_verbosity),
+                BuildSystem::CMake | BuildSystem::Meson => run_command(
+                    &build_dir.join(executable).to_string_lossy(),
+                    args,
+                    dry_run,
+                    target_verbosity,
+                ),
             }
         }
         Subcommand::Clean => match build_system {
-            BuildSystem::Make => run_command("make", &["clean"]),
-            BuildSystem::CMake => todo!(),
-            BuildSystem::Meson => {
-                run_command("meson", &["compile", "-C", build_dir_str, "--clean"])
-            }
+            BuildSystem::Make => run_command("make", &["clean"], dry_run, verbosity),
+            BuildSystem::CMake => run_command(
+                "cmake",
+                &["--build", build_dir_str, "--target", "clean"],
+                dry_run,
+                verbosity,
+            ),
+            BuildSystem::Meson => run_command(
+                "meson",
+                &["compile", "-C", build_dir_str, "--clean"],
+                dry_run,
+                verbosity,
+            ),
         },
         Subcommand::Install { prefix } => {
-            perform_subcommand(&Subcommand::Build, build_system, build_mode, n_jobs)
-                .with_context(|| "While building the binary")?;
+            perform_subcommand_inner(
+                &Subcommand::Build,
+                build_system,
+                build_mode,
+                n_jobs,
+                dry_run,
+                verbosity,
+            )
+            .with_context(|| "While building the binary")?;
 
             if build_mode == BuildMode::Debug {
                 println!("No build mode set, defaulting to debug mode.");
@@ -275,8 +578,24 @@ fn perform_subcommand(
                 BuildSystem::Make => run_command(
                     "make",
                     &["install", &format!("PREFIX={}", prefix.to_string_lossy())],
+                    dry_run,
+                    verbosity,
                 ),
-                BuildSystem::CMake => todo!(),
+                BuildSystem::CMake => {
+                    run_command(
+                        "cmake",
+                        &[
+                            "-S",
+                            ".",
+                            "-B",
+                            build_dir_str,
+                            &format!("-DCMAKE_INSTALL_PREFIX={}", prefix.to_string_lossy()),
+                        ],
+                        dry_run,
+                        verbosity,
+                    )?;
+                    run_command("cmake", &["--install", build_dir_str], dry_run, verbosity)
+                }
                 BuildSystem::Meson => {
                     run_command(
                         "meson",
@@ -286,8 +605,10 @@ fn perform_subcommand(
                             &format!("prefix={}", prefix.to_string_lossy()),
                             build_dir_str,
                         ],
+                        dry_run,
+                        verbosity,
                     )?;
-                    run_command("meson", &["install", "-C", build_dir_str])
+                    run_command("meson", &["install", "-C", build_dir_str], dry_run, verbosity)
                 }
             }
         }
@@ -306,8 +627,17 @@ fn main() -> Result<()> {
 
     let n_jobs = match opts.n_jobs {
         Some(n) => n,
-        None => num_cpus::get(),
+        None => default_n_jobs(),
     };
 
-    perform_subcommand(&opts.subcommand, build_system, opts.build_mode, n_jobs)
+    let verbosity = Verbosity::from_opts(opts.verbose, opts.quiet);
+
+    perform_subcommand(
+        &opts.subcommand,
+        build_system,
+        opts.build_mode,
+        n_jobs,
+        opts.dry_run,
+        verbosity,
+    )
 }